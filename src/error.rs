@@ -0,0 +1,54 @@
+//! Crate-level error type. Replaces the ad-hoc `println!("... error: {}", e)`
+//! handling in the forwarding loops and the `Box<dyn Error>` return from
+//! `main` with something callers can match on and tests can assert against.
+
+use std::os::unix::io::RawFd;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// An ioctl against a TUN fd or configuration socket failed.
+    #[error("ioctl {name} failed on fd {fd}: {source}")]
+    Ioctl {
+        name: &'static str,
+        fd: RawFd,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// Reading from or writing to the TUN device failed.
+    #[error("tun i/o error: {0}")]
+    TunIo(#[source] std::io::Error),
+
+    /// Reading from or writing to the UDP socket failed.
+    #[error("udp i/o error: {0}")]
+    UdpIo(#[source] std::io::Error),
+
+    /// The Noise handshake could not be completed with the peer.
+    #[error("handshake failed: {0}")]
+    Handshake(&'static str),
+
+    /// A transport message failed to decrypt, or its counter was a replay.
+    #[error("transport message failed decryption or replay check")]
+    Decrypt,
+
+    /// This platform's `Tun` backend doesn't implement the requested
+    /// operation (e.g. multi-queue on a backend that has no such concept).
+    #[error("unsupported on this platform: {0}")]
+    Unsupported(&'static str),
+
+    /// A required piece of environment-based configuration (a key, a role)
+    /// was missing or malformed.
+    #[error("configuration error: {0}")]
+    Config(String),
+}
+
+impl Error {
+    /// Whether this error means the underlying fd or interface is broken
+    /// and a caller like a queue worker should give up rather than retry.
+    /// `TunIo`/`Ioctl` mean the device itself is in a bad state (e.g. a
+    /// detached queue fd); `UdpIo`, `Handshake` and `Decrypt` are one-off
+    /// failures the next packet gets a fresh shot at.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, Error::TunIo(_) | Error::Ioctl { .. })
+    }
+}
@@ -1,278 +1,675 @@
 #![recursion_limit = "256"]
 
-use std::error::Error;
+mod error;
+mod noise;
+mod peer;
+mod sticky_udp;
+mod tun;
+
 use std::net::SocketAddr;
-use std::os::raw::{c_char, c_int, c_short, c_uchar, c_ulong, c_ushort};
-use std::os::unix::io::{AsRawFd, FromRawFd};
-use std::sync::Arc;
-
-use libc::*;
-use nix::sys::socket::InetAddr;
-
-use tokio::io::AsyncBufReadExt;
-use tokio::{
-    fs::File,
-    io::{stdin, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
-    net::UdpSocket,
-    select,
-    // sync::mpsc,
-};
-
-macro_rules! ioctl(
-	($fd:expr, $flags:expr, $value:expr) => ({
-		let rc = libc::ioctl($fd, $flags, $value);
-		if rc < 0 {
-			Err(std::io::Error::last_os_error())
-		} else {
-			Ok(())
-		}
-	})
-);
-
-type IfName = [c_char; IFNAMSIZ];
-
-#[repr(C)]
-#[derive(Copy, Clone, Debug)]
-pub struct ifmap {
-    pub mem_start: c_ulong,
-    pub mem_end: c_ulong,
-    pub base_addr: c_ushort,
-    pub irq: c_uchar,
-    pub dma: c_uchar,
-    pub port: c_uchar,
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use tokio::select;
+use tokio::sync::{mpsc, oneshot, watch};
+
+use error::Error;
+use noise::{Handshake, KeyConfig, Session};
+use peer::PeerTable;
+use sticky_udp::{PktInfo, StickyUdpSocket};
+use tun::{PlatformTun, Tun};
+
+const LOCAL_INDEX: u32 = 1;
+
+// Leading type byte prepended to every wire datagram (mirrors real
+// WireGuard's message type field). This is what lets `run_udp_demux` be the
+// single reader of the shared UDP socket and route each datagram to the
+// right consumer, instead of every queue worker and the rekey task racing
+// independent `recv_from` calls on the same fd.
+const MSG_TYPE_HANDSHAKE_INITIATION: u8 = 1;
+const MSG_TYPE_HANDSHAKE_RESPONSE: u8 = 2;
+const MSG_TYPE_TRANSPORT: u8 = 4;
+
+/// Runs the bootstrap handshake for our one configured peer and returns the
+/// resulting transport session plus the peer address it was established
+/// with. Only used once, before any queue worker or the demux task has
+/// started reading the socket, so it's safe for it to do its own
+/// `recv_from` here. `we_initiate` picks which side of the IK exchange we
+/// play; both sides of the tunnel talk directly to each other over the same
+/// `udp_socket` so the handshake messages are exchanged in-band.
+async fn run_handshake(
+    keys: &KeyConfig,
+    udp_socket: &StickyUdpSocket,
+    remote_addr: SocketAddr,
+    we_initiate: bool,
+) -> Result<(Session, SocketAddr), Error> {
+    if we_initiate {
+        let (handshake, init) = Handshake::initiate(keys, LOCAL_INDEX);
+        let mut buf = vec![MSG_TYPE_HANDSHAKE_INITIATION];
+        buf.extend_from_slice(&init.sender_index.to_le_bytes());
+        buf.extend_from_slice(&init.unencrypted_ephemeral);
+        buf.extend_from_slice(&init.encrypted_static);
+        buf.extend_from_slice(&init.encrypted_timestamp);
+        udp_socket
+            .send_to(&buf, remote_addr, None)
+            .await
+            .map_err(Error::UdpIo)?;
+
+        let mut resp_buf = vec![0u8; 1024];
+        let (n, _, _) = udp_socket
+            .recv_from(&mut resp_buf)
+            .await
+            .map_err(Error::UdpIo)?;
+        if n < 1 || resp_buf[0] != MSG_TYPE_HANDSHAKE_RESPONSE {
+            return Err(Error::Handshake("expected a handshake response message"));
+        }
+        let resp = parse_handshake_response(&resp_buf[1..n])
+            .ok_or(Error::Handshake("handshake response message was malformed"))?;
+        let keys_out = handshake
+            .finalize(keys, &resp)
+            .ok_or(Error::Handshake("handshake response did not verify"))?;
+        Ok((
+            Session::new(LOCAL_INDEX, resp.sender_index, keys_out),
+            remote_addr,
+        ))
+    } else {
+        let mut init_buf = vec![0u8; 1024];
+        let (n, peer, _) = udp_socket
+            .recv_from(&mut init_buf)
+            .await
+            .map_err(Error::UdpIo)?;
+        if n < 1 || init_buf[0] != MSG_TYPE_HANDSHAKE_INITIATION {
+            return Err(Error::Handshake("expected a handshake initiation message"));
+        }
+        let init = parse_handshake_initiation(&init_buf[1..n])
+            .ok_or(Error::Handshake("handshake initiation message was malformed"))?;
+        let (resp, keys_out) = Handshake::respond(keys, LOCAL_INDEX, &init)
+            .ok_or(Error::Handshake("handshake initiation did not verify"))?;
+        let mut buf = vec![MSG_TYPE_HANDSHAKE_RESPONSE];
+        buf.extend_from_slice(&resp.sender_index.to_le_bytes());
+        buf.extend_from_slice(&resp.receiver_index.to_le_bytes());
+        buf.extend_from_slice(&resp.unencrypted_ephemeral);
+        buf.extend_from_slice(&resp.encrypted_nothing);
+        udp_socket
+            .send_to(&buf, peer, None)
+            .await
+            .map_err(Error::UdpIo)?;
+        Ok((Session::new(LOCAL_INDEX, init.sender_index, keys_out), peer))
+    }
 }
 
-#[repr(C)]
-#[derive(Copy, Clone)]
-pub union ifr_ifru {
-    pub ifr_addr: libc::sockaddr,
-    pub ifr_dstaddr: libc::sockaddr,
-    pub ifr_broadaddr: libc::sockaddr,
-    pub ifr_netmask: libc::sockaddr,
-    pub ifr_hwaddr: libc::sockaddr,
-    pub ifr_flags: c_short,
-    pub ifr_ifindex: c_int,
-    pub ifr_metric: c_int,
-    pub ifr_mtu: c_int,
-    pub ifr_map: ifmap,
-    pub ifr_slave: IfName,
-    pub ifr_newname: IfName,
-    pub ifr_data: *mut c_char,
+/// Which side of the IK handshake we play, selected with `TUNNEL_ROLE`
+/// (`initiator` or `responder`, case-insensitive) since two instances of
+/// this tunnel talking to each other need one on each side. Defaults to
+/// `initiator` to match prior behavior when unset.
+fn we_initiate() -> bool {
+    match std::env::var("TUNNEL_ROLE") {
+        Ok(role) if role.eq_ignore_ascii_case("responder") => false,
+        _ => true,
+    }
 }
 
-#[repr(C)]
-#[derive(Copy, Clone)]
-pub struct ifreq {
-    pub ifr_name: IfName,
-    pub ifr_ifru: ifr_ifru,
+/// Loads a 32-byte X25519 key (our static private key, or the peer's static
+/// public key) from an environment variable holding its 64-character hex
+/// encoding. There's no hex crate in this tree's dependency set, so this
+/// decodes by hand rather than pulling one in for two call sites.
+fn load_x25519_key_env(var: &str) -> Result<[u8; 32], Error> {
+    let hex = std::env::var(var)
+        .map_err(|_| Error::Config(format!("{} is not set", var)))?;
+    if hex.len() != 64 {
+        return Err(Error::Config(format!(
+            "{} must be 64 hex characters (32 bytes), got {}",
+            var,
+            hex.len()
+        )));
+    }
+    let mut key = [0u8; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|_| Error::Config(format!("{} is not valid hex", var)))?;
+    }
+    Ok(key)
 }
 
-impl ifreq {
-    pub fn with_if_name(iface: &str) -> ifreq {
-        let mut ifr = ifreq::default();
-        for (a, c) in ifr.ifr_name.iter_mut().zip(iface.bytes()) {
-            *a = c as i8;
-        }
-        ifr
+// Fixed wire sizes: sender/receiver index (4 bytes each) + a 32-byte X25519
+// public key + AEAD ciphertexts (always plaintext length + 16-byte tag).
+// These are the sizes of the payload that follows the leading type byte.
+const HANDSHAKE_INITIATION_LEN: usize = 4 + 32 + (32 + 16) + (12 + 16);
+const HANDSHAKE_RESPONSE_LEN: usize = 4 + 4 + 32 + 16;
+
+/// Parses a `HandshakeInitiation` payload (everything after the leading type
+/// byte). Returns `None` for anything that isn't exactly the expected
+/// length, so a short or malformed datagram on the listening port (a
+/// scanner, a stray packet) is dropped instead of panicking the slice
+/// indexing below.
+fn parse_handshake_initiation(buf: &[u8]) -> Option<noise::HandshakeInitiation> {
+    if buf.len() != HANDSHAKE_INITIATION_LEN {
+        return None;
     }
+    Some(noise::HandshakeInitiation {
+        sender_index: u32::from_le_bytes(buf[0..4].try_into().unwrap()),
+        unencrypted_ephemeral: buf[4..36].try_into().unwrap(),
+        encrypted_static: buf[36..84].to_vec(),
+        encrypted_timestamp: buf[84..].to_vec(),
+    })
 }
 
-impl Default for ifreq {
-    fn default() -> ifreq {
-        unsafe { std::mem::zeroed() }
+/// Parses a `HandshakeResponse` payload; see `parse_handshake_initiation`.
+fn parse_handshake_response(buf: &[u8]) -> Option<noise::HandshakeResponse> {
+    if buf.len() != HANDSHAKE_RESPONSE_LEN {
+        return None;
     }
+    Some(noise::HandshakeResponse {
+        sender_index: u32::from_le_bytes(buf[0..4].try_into().unwrap()),
+        receiver_index: u32::from_le_bytes(buf[4..8].try_into().unwrap()),
+        unencrypted_ephemeral: buf[8..40].try_into().unwrap(),
+        encrypted_nothing: buf[40..].to_vec(),
+    })
 }
 
-const IFF_UP: i16 = 1;
-const IFF_RUNNING: i16 = 1 << 6;
+/// Number of TUN queues to open, overridable with `TUN_QUEUES`. Only
+/// meaningful on backends that actually support multi-queue (Linux); on
+/// others the extra `open()` calls fail with `Error::Unsupported` and we
+/// just keep running on the one queue we already have.
+fn queue_count() -> usize {
+    std::env::var("TUN_QUEUES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|&n: &usize| n > 0)
+        .unwrap_or(4)
+}
 
-/* TUNSETIFF ifr flags */
-const IFF_TUN: i16 = 0x0001;
-const IFF_NO_PI: i16 = 0x1000;
-const IFF_MULTI_QUEUE: i16 = 0x0100;
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let primary = PlatformTun::open(None).await?;
+    primary.set_flags(true)?;
 
-/* Ioctl defines */
-const TUNSETIFF: u64 = 0x4004_54ca;
+    // Dual-stack interface config: one or more CIDRs, each configured
+    // through the `Tun` call matching its address family.
+    let cidrs: Vec<ipnet::IpNet> = vec![
+        "10.0.5.1/24".parse().unwrap(),
+        "fd00:5::1/64".parse().unwrap(),
+    ];
+    for cidr in &cidrs {
+        primary.set_addr(cidr.addr(), cidr.prefix_len())?;
+        if let ipnet::IpNet::V4(cidr4) = cidr {
+            primary.set_netmask(cidr4.netmask())?;
+        }
+    }
+    let if_name = primary.name().to_string();
 
-/* Socket configuration controls. */
-const SIOCGIFFLAGS: u64 = 0x8914; /* get flags */
-const SIOCSIFFLAGS: u64 = 0x8914; /* set flags */
-const SIOCSIFADDR: u64 = 0x8916; /* set PA address */
-const SIOCSIFNETMASK: u64 = 0x891c; /* set network PA mask */
+    let udp_socket =
+        StickyUdpSocket::bind("0.0.0.0:9090".parse().unwrap()).map_err(Error::UdpIo)?;
+    println!(
+        "Listening on {}",
+        udp_socket.local_addr().map_err(Error::UdpIo)?
+    );
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let tun_file = File::open("/dev/net/tun").await?;
-    let rawfd = tun_file.as_raw_fd();
-
-    // iface up
-    let mut req = ifreq::with_if_name("");
-    req.ifr_ifru.ifr_flags = IFF_TUN | IFF_NO_PI | IFF_MULTI_QUEUE;
-    unsafe { ioctl!(rawfd, TUNSETIFF, &req) }?;
-
-    // set ip
-    const IPPROTO_IP: c_int = 0;
-    let sock4 = unsafe { socket(AF_INET, SOCK_DGRAM, IPPROTO_IP) };
-    unsafe {
-        ioctl!(sock4, SIOCGIFFLAGS, &req)?;
-        req.ifr_ifru.ifr_flags |= IFF_UP | IFF_RUNNING;
-        ioctl!(sock4, SIOCSIFFLAGS, &req)?;
+    // Noise static keypair + the one peer we're configured to talk to,
+    // loaded from the environment rather than generated/hardcoded: a fresh
+    // random key every start, or an all-zero peer key, could never
+    // complete a real handshake with another instance of this binary.
+    let private_key = StaticSecret::from(load_x25519_key_env("LOCAL_PRIVATE_KEY")?);
+    let peer_public_key = PublicKey::from(load_x25519_key_env("PEER_PUBLIC_KEY")?);
+    let keys = Arc::new(KeyConfig::new(private_key, peer_public_key));
+
+    // Configured bootstrap endpoint, used only until we learn the peer's
+    // real source address from an authenticated packet.
+    let configured_addr: SocketAddr = "127.0.0.1:9091".parse().unwrap();
+    let we_initiate = we_initiate();
+    let (session, established_addr) =
+        run_handshake(&keys, &udp_socket, configured_addr, we_initiate).await?;
+    println!("handshake complete, session established with {}", established_addr);
+    let session = Arc::new(Mutex::new(session));
+
+    let peer_table = Arc::new(PeerTable::new());
+    peer_table.observe(&keys.peer_public_key, established_addr, None);
+    let peer_public_key = keys.peer_public_key;
+
+    let udp_socket = Arc::new(udp_socket);
+
+    // One queue is the device we already opened and configured above; the
+    // rest are opened fresh against the now-named interface. On backends
+    // with no multi-queue concept (macOS), `open(Some(..))` reports
+    // `Error::Unsupported` and we just keep running on the one queue.
+    let queues = queue_count();
+    let mut tun_queues = Vec::with_capacity(queues);
+    tun_queues.push(primary);
+    for _ in 1..queues {
+        match PlatformTun::open(Some(&if_name)).await {
+            Ok(q) => tun_queues.push(q),
+            Err(Error::Unsupported(_)) => break,
+            Err(e) => return Err(e),
+        }
     }
+    println!(
+        "forwarding across {} tun queue(s) on {}",
+        tun_queues.len(),
+        if_name
+    );
 
-    let cidr: ipnet::IpNet = "10.0.5.1/24".parse().unwrap();
-    let addr = InetAddr::from_std(&(cidr.addr(), 0).into());
-    match addr {
-        InetAddr::V4(sockaddr_in) => unsafe {
-            req.ifr_ifru.ifr_addr = std::mem::transmute(sockaddr_in);
-            ioctl!(sock4, SIOCSIFADDR, &req)?;
-        },
-        InetAddr::V6(_) => {}
-    };
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
 
-    // set mask
-    let netmask = InetAddr::from_std(&(cidr.netmask(), 0).into());
-    match netmask {
-        InetAddr::V4(sockaddr_in) => unsafe {
-            req.ifr_ifru.ifr_netmask = std::mem::transmute(sockaddr_in);
-            ioctl!(sock4, SIOCSIFNETMASK, &req)?;
-        },
-        InetAddr::V6(_) => (),
-    };
+    // One inbound channel per queue: `run_udp_demux` is the only task that
+    // ever reads `udp_socket`, and hands decrypted transport plaintext to
+    // the workers this way instead of every worker racing its own
+    // `recv_from` on the shared socket.
+    let mut inbound_txs = Vec::with_capacity(tun_queues.len());
+    let mut workers = Vec::with_capacity(tun_queues.len());
+    for tun_queue in tun_queues {
+        let (inbound_tx, inbound_rx) = mpsc::unbounded_channel();
+        inbound_txs.push(inbound_tx);
+
+        let udp_socket = udp_socket.clone();
+        let session = session.clone();
+        let peer_table = peer_table.clone();
+        let shutdown_rx = shutdown_rx.clone();
+        workers.push(tokio::spawn(run_queue_worker(
+            tun_queue,
+            udp_socket,
+            session,
+            peer_table,
+            peer_public_key,
+            configured_addr,
+            inbound_rx,
+            shutdown_rx,
+        )));
+    }
 
-    let mut tun_reader = BufReader::new(unsafe { File::from_raw_fd(rawfd) });
-    let mut tun_writer = BufWriter::new(unsafe { File::from_raw_fd(rawfd) });
-    // let mut stdin_reader = BufReader::new(stdin());
+    // Slot for a rekey handshake the demux task should resolve once the
+    // peer's `HandshakeResponse` comes back; `None` whenever no rekey is in
+    // flight.
+    let pending_handshake = Arc::new(Mutex::new(None));
 
-    let udp_socket = UdpSocket::bind("0.0.0.0:9090").await?;
-    println!("Listening on {}", udp_socket.local_addr()?);
-    let udp_receiver = Arc::new(udp_socket);
-    let udp_sender = udp_receiver.clone();
-    // let (tx, mut rx) = mpsc::channel::<(Vec<u8>, SocketAddr)>(1_000);
+    let demux_task = tokio::spawn(run_udp_demux(
+        udp_socket.clone(),
+        keys.clone(),
+        session.clone(),
+        peer_table.clone(),
+        pending_handshake.clone(),
+        inbound_txs,
+        shutdown_rx.clone(),
+    ));
 
-    // udp send
-    /*tokio::spawn(async move {
-        while let Some((data, addr)) = rx.recv().await {
-            match udp_sender.send_to(&data, &addr).await {
-                Ok(n) => {
-                    println!("{:?} bytes sent", n);
+    let rekey_task = spawn_rekey_task(
+        keys.clone(),
+        udp_socket.clone(),
+        session.clone(),
+        peer_table.clone(),
+        pending_handshake,
+        we_initiate,
+        configured_addr,
+        shutdown_rx.clone(),
+    );
+
+    tokio::signal::ctrl_c()
+        .await
+        .expect("failed to listen for ctrl-c");
+    println!("shutting down, detaching {} tun queue(s)", workers.len());
+    let _ = shutdown_tx.send(true);
+    for worker in workers {
+        let _ = worker.await;
+    }
+    let _ = rekey_task.await;
+    let _ = demux_task.await;
+
+    Ok(())
+}
+
+/// A rekey handshake we've sent and are waiting on the matching
+/// `HandshakeResponse` for. Held by `run_udp_demux` so it can finalize the
+/// handshake when the response arrives and hand the result back to whoever
+/// is waiting on `notify`.
+struct PendingHandshake {
+    handshake: Handshake,
+    notify: oneshot::Sender<Result<(Session, SocketAddr), Error>>,
+}
+
+const HANDSHAKE_RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Watches the session for the rekey thresholds in `Session::needs_rekey`
+/// (time or message count) and, when we're the initiator, starts a fresh
+/// handshake and swaps the result into the shared `session` in place. The
+/// responder side has no initiative here: it just waits for the peer to
+/// start a new handshake once its own session goes stale, answered inline
+/// by `run_udp_demux`.
+///
+/// This doesn't call `run_handshake` (which does its own `recv_from`):
+/// after startup, `run_udp_demux` is the only task allowed to read
+/// `udp_socket`, so this only sends the initiation and then waits on a
+/// `oneshot` that the demux task resolves once it sees the response.
+fn spawn_rekey_task(
+    keys: Arc<KeyConfig>,
+    udp_socket: Arc<StickyUdpSocket>,
+    session: Arc<Mutex<Session>>,
+    peer_table: Arc<PeerTable>,
+    pending_handshake: Arc<Mutex<Option<PendingHandshake>>>,
+    we_initiate: bool,
+    configured_addr: SocketAddr,
+    mut shutdown_rx: watch::Receiver<bool>,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            select! {
+                _ = tokio::time::sleep(Duration::from_secs(10)) => {}
+                _ = shutdown_rx.changed() => break,
+            }
+
+            if !we_initiate || !session.lock().unwrap().needs_rekey() {
+                continue;
+            }
+
+            let remote_addr = peer_table
+                .endpoint(&keys.peer_public_key)
+                .unwrap_or(configured_addr);
+
+            let (handshake, init) = Handshake::initiate(&keys, LOCAL_INDEX);
+            let mut buf = vec![MSG_TYPE_HANDSHAKE_INITIATION];
+            buf.extend_from_slice(&init.sender_index.to_le_bytes());
+            buf.extend_from_slice(&init.unencrypted_ephemeral);
+            buf.extend_from_slice(&init.encrypted_static);
+            buf.extend_from_slice(&init.encrypted_timestamp);
+
+            let (notify, response) = oneshot::channel();
+            *pending_handshake.lock().unwrap() = Some(PendingHandshake { handshake, notify });
+
+            if let Err(e) = udp_socket.send_to(&buf, remote_addr, None).await {
+                println!("{}", Error::UdpIo(e));
+                pending_handshake.lock().unwrap().take();
+                continue;
+            }
+
+            match tokio::time::timeout(HANDSHAKE_RESPONSE_TIMEOUT, response).await {
+                Ok(Ok(Ok((new_session, established_addr)))) => {
+                    *session.lock().unwrap() = new_session;
+                    peer_table.observe(&keys.peer_public_key, established_addr, None);
+                    println!("rekeyed session with {}", established_addr);
                 }
-                Err(e) => {
-                    println!("udp read error: {}", e);
+                Ok(Ok(Err(e))) => {
+                    println!("rekey handshake failed, keeping old session: {}", e);
                 }
-            }
-        }
-    });*/
-
-    // udp receive
-    tokio::spawn(
-        async move {
-            loop {
-                let mut udp_buf = vec![0u8; 1024];
-                match udp_receiver.recv_from(&mut udp_buf).await  {
-                    Ok((n, peer)) => {
-                        if n > 0 {
-                            println!("received {} bytes {:?} from {}", n, &udp_buf[..n], peer);
-                        }
-                        match tun_writer.write(&udp_buf[..n]).await {
-                            Ok(n) => {
-                                println!("write {} bytes to tun", n);
-                            },
-                            Err(e) => {
-                                println!("tun write error: {}", e);
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        println!("udp read error: {}", e);
-                    }
+                Ok(Err(_)) => {
+                    // The demux task dropped `notify` without resolving it
+                    // (e.g. it's shutting down) - nothing to report.
+                }
+                Err(_) => {
+                    println!("rekey handshake timed out, keeping old session");
+                    pending_handshake.lock().unwrap().take();
                 }
             }
         }
-    );
+    })
+}
 
-    // stdin read
-    /*tokio::spawn(async move {
-        loop {
-            let mut stdin_buf = String::new();
-            match stdin_reader.read_line(&mut stdin_buf).await {
-                Ok(n) => {
-                    if n > 0 {
-                        println!("read {} bytes {:?} from stdin", n, stdin_buf);
+/// The single reader of the shared UDP socket once startup is done. Routes
+/// each datagram by its leading type byte: a `HandshakeResponse` resolves
+/// whatever rekey is waiting in `pending_handshake`; a peer-initiated
+/// `HandshakeInitiation` (a mid-session rekey from the peer) is answered
+/// inline; a `Transport` message is decrypted and handed round-robin to a
+/// queue worker's inbound channel to be written to its TUN device.
+///
+/// Centralizing this is what fixes the race a previous version of this
+/// tunnel had: multiple queue workers and the rekey task each calling
+/// `udp_socket.recv_from` independently meant whichever task happened to
+/// win a given `recv_from` got the datagram, even if it was a handshake
+/// response meant for a task that had no idea to look for it.
+async fn run_udp_demux(
+    udp_socket: Arc<StickyUdpSocket>,
+    keys: Arc<KeyConfig>,
+    session: Arc<Mutex<Session>>,
+    peer_table: Arc<PeerTable>,
+    pending_handshake: Arc<Mutex<Option<PendingHandshake>>>,
+    inbound_txs: Vec<mpsc::UnboundedSender<Vec<u8>>>,
+    mut shutdown_rx: watch::Receiver<bool>,
+) {
+    let mut next_queue = 0usize;
+    loop {
+        let mut buf = vec![0u8; 1500];
+
+        select! {
+            r = udp_socket.recv_from(&mut buf) => match r {
+                Ok((n, peer, pktinfo)) => {
+                    if n < 1 {
+                        // Too short to even carry a type byte.
+                        println!("{}", Error::Decrypt);
+                        continue;
                     }
-                    let buf: [u8; 84] = [
-                        69, 0, 0, 84, 97, 87, 64, 0, 64, 1, 187, 79, 10, 0, 5, 1, 10, 0, 5, 2, 8,
-                        0, 45, 248, 90, 168, 0, 1, 23, 64, 70, 96, 0, 0, 0, 0, 70, 235, 12, 0, 0,
-                        0, 0, 0, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31,
-                        32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50,
-                        51, 52, 53, 54, 5,
-                    ];
-                    match tun_writer.write(&buf).await {
-                        Ok(n) => {
-                            println!("write {} bytes to tun", n);
+                    match buf[0] {
+                        MSG_TYPE_HANDSHAKE_INITIATION => {
+                            handle_peer_initiation(
+                                &buf[1..n],
+                                peer,
+                                pktinfo,
+                                &udp_socket,
+                                &keys,
+                                &session,
+                                &peer_table,
+                            )
+                            .await;
                         }
-                        Err(e) => {
-                            println!("tun write error: {}", e);
+                        MSG_TYPE_HANDSHAKE_RESPONSE => {
+                            handle_handshake_response(
+                                &buf[1..n],
+                                peer,
+                                pktinfo,
+                                &pending_handshake,
+                                &keys,
+                                &peer_table,
+                            );
                         }
+                        MSG_TYPE_TRANSPORT => {
+                            if n < 5 {
+                                // Too short to carry even the receiver index.
+                                println!("{}", Error::Decrypt);
+                                continue;
+                            }
+                            let plaintext = session.lock().unwrap().decrypt_transport(&buf[5..n]);
+                            let plaintext = match plaintext {
+                                Some(p) => p,
+                                None => {
+                                    // Drop and move on: one bad or replayed
+                                    // message shouldn't take down the tunnel.
+                                    println!("{}", Error::Decrypt);
+                                    continue;
+                                }
+                            };
+                            // Only an authenticated packet can move the
+                            // peer's endpoint, so this can't be spoofed by
+                            // UDP source address alone.
+                            peer_table.observe(&keys.peer_public_key, peer, Some(pktinfo));
+
+                            if !inbound_txs.is_empty() {
+                                next_queue = (next_queue + 1) % inbound_txs.len();
+                                // The matching worker may have exited (a
+                                // fatal tun error); dropping the plaintext
+                                // in that case is fine, the peer will resend.
+                                let _ = inbound_txs[next_queue].send(plaintext);
+                            }
+                        }
+                        _ => println!("{}", Error::Decrypt),
                     }
                 }
                 Err(e) => {
-                    println!("stdin read error: {}", e);
+                    // A recv error on the shared UDP socket is recoverable:
+                    // the next datagram gets its own attempt.
+                    println!("{}", Error::UdpIo(e));
                 }
-            }
+            },
+
+            _ = shutdown_rx.changed() => break,
         }
-    });*/
+    }
+}
+
+/// Resolves a rekey waiting in `pending_handshake` once its matching
+/// `HandshakeResponse` arrives. A response with nothing waiting for it (no
+/// rekey in flight, already timed out, or a duplicate/stray datagram) is
+/// silently dropped.
+fn handle_handshake_response(
+    payload: &[u8],
+    peer_addr: SocketAddr,
+    pktinfo: PktInfo,
+    pending_handshake: &Mutex<Option<PendingHandshake>>,
+    keys: &KeyConfig,
+    peer_table: &PeerTable,
+) {
+    let resp = match parse_handshake_response(payload) {
+        Some(r) => r,
+        None => {
+            println!("{}", Error::Handshake("handshake response message was malformed"));
+            return;
+        }
+    };
+
+    let pending = match pending_handshake.lock().unwrap().take() {
+        Some(p) => p,
+        None => return,
+    };
 
+    match pending.handshake.finalize(keys, &resp) {
+        Some(keys_out) => {
+            let session = Session::new(LOCAL_INDEX, resp.sender_index, keys_out);
+            peer_table.observe(&keys.peer_public_key, peer_addr, Some(pktinfo));
+            let _ = pending.notify.send(Ok((session, peer_addr)));
+        }
+        None => {
+            let _ = pending
+                .notify
+                .send(Err(Error::Handshake("handshake response did not verify")));
+        }
+    }
+}
+
+/// Answers a peer-initiated `HandshakeInitiation` arriving mid-session (the
+/// peer rekeying on its own schedule), swapping the resulting session into
+/// `session` in place. This is the responder-side counterpart to
+/// `spawn_rekey_task`'s initiator-side rekeys.
+async fn handle_peer_initiation(
+    payload: &[u8],
+    peer_addr: SocketAddr,
+    pktinfo: PktInfo,
+    udp_socket: &StickyUdpSocket,
+    keys: &KeyConfig,
+    session: &Mutex<Session>,
+    peer_table: &PeerTable,
+) {
+    let init = match parse_handshake_initiation(payload) {
+        Some(i) => i,
+        None => {
+            println!("{}", Error::Handshake("handshake initiation message was malformed"));
+            return;
+        }
+    };
+
+    let (resp, keys_out) = match Handshake::respond(keys, LOCAL_INDEX, &init) {
+        Some(r) => r,
+        None => {
+            println!("{}", Error::Handshake("handshake initiation did not verify"));
+            return;
+        }
+    };
+
+    let mut buf = vec![MSG_TYPE_HANDSHAKE_RESPONSE];
+    buf.extend_from_slice(&resp.sender_index.to_le_bytes());
+    buf.extend_from_slice(&resp.receiver_index.to_le_bytes());
+    buf.extend_from_slice(&resp.unencrypted_ephemeral);
+    buf.extend_from_slice(&resp.encrypted_nothing);
+    if let Err(e) = udp_socket.send_to(&buf, peer_addr, Some(pktinfo)).await {
+        println!("{}", Error::UdpIo(e));
+        return;
+    }
+
+    *session.lock().unwrap() = Session::new(LOCAL_INDEX, init.sender_index, keys_out);
+    peer_table.observe(&keys.peer_public_key, peer_addr, Some(pktinfo));
+    println!("rekeyed (peer-initiated) session with {}", peer_addr);
+}
+
+/// Forwards packets for a single TUN queue: TUN reads go out over the
+/// shared UDP socket to the peer's last-learned endpoint (falling back to
+/// `configured_addr` until one is learned); decrypted transport plaintext
+/// arrives over `inbound_rx` from `run_udp_demux`, the only task that reads
+/// `udp_socket`, and is written back to this queue's TUN device. Watching
+/// `shutdown_rx` lets each worker detach its own queue before exiting,
+/// instead of the caller aborting the task out from under it. TUN I/O
+/// errors are checked with `Error::is_fatal`: a broken fd exits the worker
+/// instead of busy-looping on the same error forever, while UDP errors are
+/// treated as one-off and the loop keeps going.
+async fn run_queue_worker<T: Tun>(
+    mut tun_queue: T,
+    udp_socket: Arc<StickyUdpSocket>,
+    session: Arc<Mutex<Session>>,
+    peer_table: Arc<PeerTable>,
+    peer_public_key: PublicKey,
+    configured_addr: SocketAddr,
+    mut inbound_rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    mut shutdown_rx: watch::Receiver<bool>,
+) {
     loop {
         let mut tun_buf = vec![0u8; 1500];
-        // let mut stdin_buf = String::new();
 
         select! {
-            r = tun_reader.read(&mut tun_buf) => match r {
+            r = tun_queue.read(&mut tun_buf) => match r {
                 Ok(n) => {
                     if n > 0 {
-                        println!("read {} bytes {:?} from tun", n, &tun_buf[..n]);
+                        println!("read {} bytes from tun", n);
                     }
-                    let remote_addr:SocketAddr = "127.0.0.1:9091".parse().unwrap();
-                    /*match tx.send((tun_buf, remote_addr)).await {
-                        Ok(()) => {
-                        }
-                        Err(e) => {
-                            println!("channel send error: {}", e);
-                        }
-                    }*/
-                    match udp_sender.send_to(&tun_buf[..n], &remote_addr).await {
+                    let dest = peer_table.endpoint(&peer_public_key).unwrap_or(configured_addr);
+                    let pktinfo = peer_table.pktinfo(&peer_public_key);
+                    let transport = session.lock().unwrap().encrypt_transport(&tun_buf[..n]);
+                    let mut datagram = Vec::with_capacity(1 + transport.len());
+                    datagram.push(MSG_TYPE_TRANSPORT);
+                    datagram.extend_from_slice(&transport);
+                    match udp_socket.send_to(&datagram, dest, pktinfo).await {
                         Ok(n) => {
                             println!("{:?} bytes sent", n);
                         }
                         Err(e) => {
-                            println!("udp read error: {}", e);
+                            // A single failed send is recoverable: the next
+                            // TUN packet gets its own attempt.
+                            println!("{}", Error::UdpIo(e));
                         }
                     }
                 }
                 Err(e) => {
-                    println!("tun read error: {}", e);
+                    println!("{}", e);
+                    if e.is_fatal() {
+                        // The tun fd itself is broken (e.g. detached out
+                        // from under us); retrying would just busy-loop
+                        // printing the same error forever.
+                        break;
+                    }
                 }
             },
 
-            /*r = stdin_reader.read_line(&mut stdin_buf) => match r {
-                Ok(n) => {
-                    if n > 0 {
-                        println!("read {} bytes {:?} from stdin", n, stdin_buf);
-                    }
-                    let buf: [u8; 84] = [69, 0, 0, 84, 97, 87, 64, 0, 64, 1, 187, 79, 10, 0, 5, 1, 10, 0, 5, 2, 8, 0, 45, 248, 90, 168, 0, 1, 23, 64, 70, 96, 0, 0, 0, 0, 70, 235, 12, 0, 0, 0, 0, 0, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 51, 52, 53, 54, 5];
-                    match tun_writer.write(&buf).await {
+            plaintext = inbound_rx.recv() => match plaintext {
+                Some(plaintext) => {
+                    match tun_queue.write(&plaintext).await {
                         Ok(n) => {
                             println!("write {} bytes to tun", n);
                         },
                         Err(e) => {
-                            println!("tun write error: {}", e);
+                            println!("{}", e);
+                            if e.is_fatal() {
+                                break;
+                            }
                         }
                     }
                 }
-                Err(e) => {
-                    println!("stdin read error: {}", e);
+                None => {
+                    // `run_udp_demux` has exited and dropped its senders;
+                    // nothing more will ever arrive on this channel.
+                    break;
                 }
-            },*/
+            },
+
+            _ = shutdown_rx.changed() => {
+                if let Err(e) = tun_queue.detach() {
+                    println!("failed to detach tun queue on {}: {}", tun_queue.name(), e);
+                }
+                break;
+            }
         }
     }
 }
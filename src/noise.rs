@@ -0,0 +1,531 @@
+//! Minimal WireGuard-style Noise_IK implementation: Curve25519 DH, BLAKE2s
+//! for hashing/mixing, ChaCha20-Poly1305 for the AEAD. This only implements
+//! enough of the handshake and transport framing to encrypt/authenticate the
+//! tunnel; it is not a general-purpose Noise library.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use blake2::{digest::consts::U32, Blake2s, Digest};
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand_core::OsRng;
+use x25519_dalek::{PublicKey, ReusableSecret, StaticSecret};
+
+const CONSTRUCTION: &[u8] = b"Noise_IKpsk2_25519_ChaChaPoly_BLAKE2s";
+const IDENTIFIER: &[u8] = b"WireGuard v1 zx2c4 Jason@zx2c4.com";
+
+const REKEY_AFTER_TIME_SECS: u64 = 120;
+const REKEY_AFTER_MESSAGES: u64 = 1 << 60;
+
+type Blake2s256 = Blake2s<U32>;
+
+/// Long-lived keys for one side of the tunnel: our static keypair and the
+/// single peer's static public key we're configured to talk to.
+pub struct KeyConfig {
+    pub private_key: StaticSecret,
+    pub public_key: PublicKey,
+    pub peer_public_key: PublicKey,
+}
+
+impl KeyConfig {
+    pub fn new(private_key: StaticSecret, peer_public_key: PublicKey) -> Self {
+        let public_key = PublicKey::from(&private_key);
+        KeyConfig {
+            private_key,
+            public_key,
+            peer_public_key,
+        }
+    }
+}
+
+fn hash(data: &[u8]) -> [u8; 32] {
+    let mut hasher = Blake2s256::new();
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+fn hmac_blake2s(key: &[u8], data: &[u8]) -> [u8; 32] {
+    // HMAC as used by the Noise HKDF chain, specialized to BLAKE2s with a
+    // 32 byte block size (matches the WireGuard whitepaper's construction).
+    const BLOCK_SIZE: usize = 32;
+    let mut ikey = [0x36u8; BLOCK_SIZE];
+    let mut okey = [0x5cu8; BLOCK_SIZE];
+    let mut keyb = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        keyb[..32].copy_from_slice(&hash(key));
+    } else {
+        keyb[..key.len()].copy_from_slice(key);
+    }
+    for i in 0..BLOCK_SIZE {
+        ikey[i] ^= keyb[i];
+        okey[i] ^= keyb[i];
+    }
+    let mut inner = Blake2s256::new();
+    inner.update(&ikey);
+    inner.update(data);
+    let inner_hash = inner.finalize();
+    let mut outer = Blake2s256::new();
+    outer.update(&okey);
+    outer.update(&inner_hash);
+    outer.finalize().into()
+}
+
+/// 2-output HKDF expand as used for the `mix_key` chain.
+fn kdf2(key: &[u8], input: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let t0 = hmac_blake2s(key, input);
+    let t1 = hmac_blake2s(&t0, &[0x01]);
+    let t2 = hmac_blake2s(&t0, &[&t1[..], &[0x02]].concat());
+    (t1, t2)
+}
+
+/// State accumulated while running the IK handshake, mirroring the
+/// `chaining_key`/`hash` pair from the Noise spec.
+struct SymmetricState {
+    chaining_key: [u8; 32],
+    hash: [u8; 32],
+}
+
+impl SymmetricState {
+    fn new() -> Self {
+        let chaining_key = hash(CONSTRUCTION);
+        let mut hash_val = [0u8; 32];
+        let mut hasher = Blake2s256::new();
+        hasher.update(&chaining_key);
+        hasher.update(IDENTIFIER);
+        hash_val.copy_from_slice(&hasher.finalize());
+        SymmetricState {
+            chaining_key,
+            hash: hash_val,
+        }
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        let mut hasher = Blake2s256::new();
+        hasher.update(&self.hash);
+        hasher.update(data);
+        self.hash = hasher.finalize().into();
+    }
+
+    fn mix_key(&mut self, dh_result: &[u8]) {
+        let (ck, _) = kdf2(&self.chaining_key, dh_result);
+        self.chaining_key = ck;
+    }
+
+    /// Mixes key material and returns a derived key usable for AEAD, as
+    /// happens on the final DH (`ss`) of the handshake.
+    fn mix_key_and_split(&mut self, dh_result: &[u8]) -> ([u8; 32], [u8; 32]) {
+        let (ck, k) = kdf2(&self.chaining_key, dh_result);
+        self.chaining_key = ck;
+        (ck, k)
+    }
+}
+
+fn aead_encrypt(key: &[u8; 32], counter: u64, plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let mut nonce_bytes = [0u8; 12];
+    nonce_bytes[4..].copy_from_slice(&counter.to_le_bytes());
+    let nonce = Nonce::from_slice(&nonce_bytes);
+    cipher
+        .encrypt(
+            nonce,
+            chacha20poly1305::aead::Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .expect("encryption does not fail")
+}
+
+fn aead_decrypt(key: &[u8; 32], counter: u64, ciphertext: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let mut nonce_bytes = [0u8; 12];
+    nonce_bytes[4..].copy_from_slice(&counter.to_le_bytes());
+    let nonce = Nonce::from_slice(&nonce_bytes);
+    cipher
+        .decrypt(
+            nonce,
+            chacha20poly1305::aead::Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .ok()
+}
+
+fn tai64n_timestamp() -> [u8; 12] {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let mut ts = [0u8; 12];
+    ts[..8].copy_from_slice(&(now.as_secs() + 0x400000000000000a).to_be_bytes());
+    ts[8..].copy_from_slice(&(now.subsec_nanos()).to_be_bytes());
+    ts
+}
+
+/// Wire format of the first handshake message, sent by the initiator.
+pub struct HandshakeInitiation {
+    pub sender_index: u32,
+    pub unencrypted_ephemeral: [u8; 32],
+    pub encrypted_static: Vec<u8>,
+    pub encrypted_timestamp: Vec<u8>,
+}
+
+/// Wire format of the second handshake message, sent by the responder.
+pub struct HandshakeResponse {
+    pub sender_index: u32,
+    pub receiver_index: u32,
+    pub unencrypted_ephemeral: [u8; 32],
+    pub encrypted_nothing: Vec<u8>,
+}
+
+/// A completed handshake, ready to derive the transport send/receive keys.
+pub struct Handshake {
+    state: SymmetricState,
+    local_ephemeral: Option<ReusableSecret>,
+    local_ephemeral_public: PublicKey,
+    remote_ephemeral_public: Option<PublicKey>,
+}
+
+impl Handshake {
+    /// Starts a handshake as the initiator (message 1, IK pattern).
+    pub fn initiate(
+        keys: &KeyConfig,
+        sender_index: u32,
+    ) -> (Handshake, HandshakeInitiation) {
+        let mut state = SymmetricState::new();
+        state.mix_hash(keys.peer_public_key.as_bytes());
+
+        // A `ReusableSecret` (not `EphemeralSecret`) because this key feeds
+        // two separate DH computations below and `finalize` later on, and
+        // `EphemeralSecret::diffie_hellman` consumes `self` on first use.
+        let local_ephemeral = ReusableSecret::new(OsRng);
+        let local_ephemeral_public = PublicKey::from(&local_ephemeral);
+        state.mix_hash(local_ephemeral_public.as_bytes());
+
+        // e -> s (ephemeral/static DH with peer's static key)
+        let es = local_ephemeral.diffie_hellman(&keys.peer_public_key);
+        state.mix_key(es.as_bytes());
+
+        let (_, key) = state.mix_key_and_split(&[]);
+        let encrypted_static = aead_encrypt(&key, 0, keys.public_key.as_bytes(), &state.hash);
+        state.mix_hash(&encrypted_static);
+
+        // s -> s (static/static DH with peer's static key)
+        let ss = keys.private_key.diffie_hellman(&keys.peer_public_key);
+        let (_, key) = state.mix_key_and_split(ss.as_bytes());
+        let timestamp = tai64n_timestamp();
+        let encrypted_timestamp = aead_encrypt(&key, 0, &timestamp, &state.hash);
+        state.mix_hash(&encrypted_timestamp);
+
+        let init = HandshakeInitiation {
+            sender_index,
+            unencrypted_ephemeral: *local_ephemeral_public.as_bytes(),
+            encrypted_static,
+            encrypted_timestamp,
+        };
+
+        let handshake = Handshake {
+            state,
+            local_ephemeral: Some(local_ephemeral),
+            local_ephemeral_public,
+            remote_ephemeral_public: None,
+        };
+
+        (handshake, init)
+    }
+
+    /// Consumes a `HandshakeInitiation` as the responder and produces the
+    /// `HandshakeResponse` plus the derived transport keys.
+    pub fn respond(
+        keys: &KeyConfig,
+        sender_index: u32,
+        msg: &HandshakeInitiation,
+    ) -> Option<(HandshakeResponse, TransportKeys)> {
+        let mut state = SymmetricState::new();
+        state.mix_hash(keys.public_key.as_bytes());
+
+        let remote_ephemeral_public = PublicKey::from(msg.unencrypted_ephemeral);
+        state.mix_hash(remote_ephemeral_public.as_bytes());
+
+        let es = keys.private_key.diffie_hellman(&remote_ephemeral_public);
+        state.mix_key(es.as_bytes());
+
+        let (_, key) = state.mix_key_and_split(&[]);
+        // In a real IK exchange we'd decrypt to learn the initiator's
+        // static key and verify it against our configured peer; here we
+        // only support a single known peer so we just check it matches.
+        let plaintext = aead_decrypt(&key, 0, &msg.encrypted_static, &state.hash)?;
+        if plaintext != keys.peer_public_key.as_bytes() {
+            return None;
+        }
+        state.mix_hash(&msg.encrypted_static);
+
+        let ss = keys.private_key.diffie_hellman(&keys.peer_public_key);
+        let (_, key) = state.mix_key_and_split(ss.as_bytes());
+        aead_decrypt(&key, 0, &msg.encrypted_timestamp, &state.hash)?;
+        state.mix_hash(&msg.encrypted_timestamp);
+
+        let local_ephemeral = ReusableSecret::new(OsRng);
+        let local_ephemeral_public = PublicKey::from(&local_ephemeral);
+        state.mix_hash(local_ephemeral_public.as_bytes());
+
+        // ee, se
+        let ee = local_ephemeral.diffie_hellman(&remote_ephemeral_public);
+        state.mix_key(ee.as_bytes());
+        let se = local_ephemeral.diffie_hellman(&keys.peer_public_key);
+        state.mix_key(se.as_bytes());
+
+        let (_, key) = state.mix_key_and_split(&[]);
+        let encrypted_nothing = aead_encrypt(&key, 0, &[], &state.hash);
+        state.mix_hash(&encrypted_nothing);
+
+        let response = HandshakeResponse {
+            sender_index,
+            receiver_index: msg.sender_index,
+            unencrypted_ephemeral: *local_ephemeral_public.as_bytes(),
+            encrypted_nothing,
+        };
+
+        let (send, recv) = derive_transport_keys(&state.chaining_key, false);
+        Some((
+            response,
+            TransportKeys {
+                sending_key: send,
+                receiving_key: recv,
+            },
+        ))
+    }
+
+    /// Consumes a `HandshakeResponse` as the initiator and derives the
+    /// transport keys, completing the handshake.
+    pub fn finalize(mut self, keys: &KeyConfig, msg: &HandshakeResponse) -> Option<TransportKeys> {
+        let local_ephemeral = self.local_ephemeral.take()?;
+        let remote_ephemeral_public = PublicKey::from(msg.unencrypted_ephemeral);
+        self.state.mix_hash(remote_ephemeral_public.as_bytes());
+
+        let ee = local_ephemeral.diffie_hellman(&remote_ephemeral_public);
+        self.state.mix_key(ee.as_bytes());
+        let se = keys.private_key.diffie_hellman(&remote_ephemeral_public);
+        self.state.mix_key(se.as_bytes());
+
+        let (_, key) = self.state.mix_key_and_split(&[]);
+        aead_decrypt(&key, 0, &msg.encrypted_nothing, &self.state.hash)?;
+        self.state.mix_hash(&msg.encrypted_nothing);
+        self.remote_ephemeral_public = Some(remote_ephemeral_public);
+
+        let (send, recv) = derive_transport_keys(&self.state.chaining_key, true);
+        Some(TransportKeys {
+            sending_key: send,
+            receiving_key: recv,
+        })
+    }
+}
+
+fn derive_transport_keys(chaining_key: &[u8; 32], is_initiator: bool) -> ([u8; 32], [u8; 32]) {
+    let (k1, k2) = kdf2(chaining_key, &[]);
+    if is_initiator {
+        (k1, k2)
+    } else {
+        (k2, k1)
+    }
+}
+
+/// The symmetric keys a session uses to encrypt outgoing / decrypt incoming
+/// transport messages, plus the rekey bookkeeping.
+pub struct TransportKeys {
+    pub sending_key: [u8; 32],
+    pub receiving_key: [u8; 32],
+}
+
+/// A sliding window over the last 128 received counters, used to reject
+/// replayed transport messages (same approach as the WireGuard whitepaper).
+pub struct ReplayWindow {
+    last: u64,
+    bitmap: u128,
+}
+
+impl ReplayWindow {
+    const WINDOW_SIZE: u64 = 128;
+
+    pub fn new() -> Self {
+        ReplayWindow {
+            last: 0,
+            bitmap: 0,
+        }
+    }
+
+    /// Returns `true` if `counter` is new and should be accepted, marking it
+    /// as seen. Returns `false` for duplicates or counters too far behind
+    /// the window.
+    pub fn check_and_update(&mut self, counter: u64) -> bool {
+        if counter == 0 && self.last == 0 && self.bitmap == 0 {
+            self.bitmap |= 1;
+            return true;
+        }
+        if counter > self.last {
+            let shift = counter - self.last;
+            self.bitmap = if shift >= Self::WINDOW_SIZE as u64 {
+                0
+            } else {
+                self.bitmap << shift
+            };
+            self.last = counter;
+            self.bitmap |= 1;
+            true
+        } else {
+            let diff = self.last - counter;
+            if diff >= Self::WINDOW_SIZE as u64 {
+                return false;
+            }
+            let bit = 1u128 << diff;
+            if self.bitmap & bit != 0 {
+                false
+            } else {
+                self.bitmap |= bit;
+                true
+            }
+        }
+    }
+}
+
+/// An established tunnel session: derived keys, the local/remote session
+/// indices, the outbound nonce counter and the inbound replay window.
+pub struct Session {
+    pub local_index: u32,
+    pub remote_index: u32,
+    pub keys: TransportKeys,
+    pub send_counter: u64,
+    pub replay_window: ReplayWindow,
+    pub established_at: SystemTime,
+    pub messages_sent: u64,
+}
+
+impl Session {
+    pub fn new(local_index: u32, remote_index: u32, keys: TransportKeys) -> Self {
+        Session {
+            local_index,
+            remote_index,
+            keys,
+            send_counter: 0,
+            replay_window: ReplayWindow::new(),
+            established_at: SystemTime::now(),
+            messages_sent: 0,
+        }
+    }
+
+    /// Whether this session is old enough, or has carried enough traffic,
+    /// that a new handshake should be triggered (mirrors WireGuard's
+    /// `REKEY_AFTER_TIME`/`REKEY_AFTER_MESSAGES`).
+    pub fn needs_rekey(&self) -> bool {
+        self.messages_sent >= REKEY_AFTER_MESSAGES
+            || self
+                .established_at
+                .elapsed()
+                .map(|d| d.as_secs() >= REKEY_AFTER_TIME_SECS)
+                .unwrap_or(true)
+    }
+
+    /// Encrypts `plaintext` into a transport message: receiver index,
+    /// nonce counter, and AEAD ciphertext.
+    pub fn encrypt_transport(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let counter = self.send_counter;
+        self.send_counter += 1;
+        self.messages_sent += 1;
+
+        let ciphertext = aead_encrypt(&self.keys.sending_key, counter, plaintext, &[]);
+        let mut out = Vec::with_capacity(4 + 8 + ciphertext.len());
+        out.extend_from_slice(&self.remote_index.to_le_bytes());
+        out.extend_from_slice(&counter.to_le_bytes());
+        out.extend_from_slice(&ciphertext);
+        out
+    }
+
+    /// Decrypts an incoming transport message body (after the 4-byte
+    /// receiver index used to look up this session). Returns `None` on a
+    /// failed decrypt or a replayed counter.
+    pub fn decrypt_transport(&mut self, body: &[u8]) -> Option<Vec<u8>> {
+        if body.len() < 8 {
+            return None;
+        }
+        let mut counter_bytes = [0u8; 8];
+        counter_bytes.copy_from_slice(&body[..8]);
+        let counter = u64::from_le_bytes(counter_bytes);
+        if !self.replay_window.check_and_update(counter) {
+            return None;
+        }
+        aead_decrypt(&self.keys.receiving_key, counter, &body[8..], &[])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_keys() -> (KeyConfig, KeyConfig) {
+        let initiator_private = StaticSecret::new(OsRng);
+        let responder_private = StaticSecret::new(OsRng);
+        let initiator_public = PublicKey::from(&initiator_private);
+        let responder_public = PublicKey::from(&responder_private);
+        (
+            KeyConfig::new(initiator_private, responder_public),
+            KeyConfig::new(responder_private, initiator_public),
+        )
+    }
+
+    #[test]
+    fn handshake_round_trip_derives_matching_transport_keys() {
+        let (initiator_keys, responder_keys) = test_keys();
+
+        let (handshake, init) = Handshake::initiate(&initiator_keys, 1);
+        let (response, responder_transport) = Handshake::respond(&responder_keys, 2, &init)
+            .expect("responder should verify a genuine initiation");
+        let initiator_transport = handshake
+            .finalize(&initiator_keys, &response)
+            .expect("initiator should verify a genuine response");
+
+        assert_eq!(
+            initiator_transport.sending_key,
+            responder_transport.receiving_key
+        );
+        assert_eq!(
+            initiator_transport.receiving_key,
+            responder_transport.sending_key
+        );
+    }
+
+    #[test]
+    fn respond_rejects_initiation_from_an_unexpected_peer() {
+        let (_, responder_keys) = test_keys();
+        let responder_public = PublicKey::from(&responder_keys.private_key);
+
+        // An initiator the responder never configured as its peer, but who
+        // still correctly targets the responder's real static key.
+        let unexpected_initiator_keys =
+            KeyConfig::new(StaticSecret::new(OsRng), responder_public);
+
+        let (_, init) = Handshake::initiate(&unexpected_initiator_keys, 1);
+        assert!(Handshake::respond(&responder_keys, 2, &init).is_none());
+    }
+
+    #[test]
+    fn replay_window_rejects_duplicates_and_stale_counters() {
+        let mut window = ReplayWindow::new();
+        assert!(window.check_and_update(0));
+        assert!(!window.check_and_update(0));
+
+        assert!(window.check_and_update(200));
+        // 200 - 50 = 150 >= WINDOW_SIZE, too far behind to accept.
+        assert!(!window.check_and_update(50));
+        // Within the window and not yet seen.
+        assert!(window.check_and_update(199));
+        // Already seen.
+        assert!(!window.check_and_update(199));
+    }
+
+    #[test]
+    fn kdf2_is_deterministic_and_splits_into_distinct_outputs() {
+        let (a1, a2) = kdf2(b"key", b"input");
+        let (b1, b2) = kdf2(b"key", b"input");
+        assert_eq!(a1, b1);
+        assert_eq!(a2, b2);
+        assert_ne!(a1, a2);
+    }
+}
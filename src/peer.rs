@@ -0,0 +1,136 @@
+//! Tracks the most recently learned UDP endpoint for each peer, so a peer
+//! whose NAT mapping or network changes keeps working without
+//! reconfiguration. This is the same "roaming" behavior WireGuard gets from
+//! updating a peer's endpoint on every authenticated packet.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+use x25519_dalek::PublicKey;
+
+use crate::sticky_udp::PktInfo;
+
+/// What we know about where to reach a peer: its last-seen source address,
+/// and the local address/interface its packets have been arriving on (so
+/// replies can be sent with a matching sticky source via `IP_PKTINFO`).
+#[derive(Clone, Copy)]
+struct PeerEndpoint {
+    addr: SocketAddr,
+    pktinfo: Option<PktInfo>,
+}
+
+/// Peer identity (static public key) -> last-seen endpoint.
+///
+/// Entries are only ever written from packets that already passed
+/// decryption, so an attacker spoofing the UDP source address can't move a
+/// peer's endpoint without also forging a valid transport message.
+pub struct PeerTable {
+    endpoints: Mutex<HashMap<[u8; 32], PeerEndpoint>>,
+}
+
+impl PeerTable {
+    pub fn new() -> Self {
+        PeerTable {
+            endpoints: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records `addr` (and, once known, the sticky `pktinfo`) as the
+    /// current endpoint for `peer`. A `pktinfo` of `None` just means this
+    /// particular call didn't learn one (e.g. a rekey handshake response,
+    /// which carries no `IP_PKTINFO` ancillary data) — it doesn't mean the
+    /// peer has none, so an already-known `pktinfo` is preserved rather than
+    /// clobbered.
+    pub fn observe(&self, peer: &PublicKey, addr: SocketAddr, pktinfo: Option<PktInfo>) {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let pktinfo = pktinfo.or_else(|| endpoints.get(peer.as_bytes()).and_then(|e| e.pktinfo));
+        endpoints.insert(*peer.as_bytes(), PeerEndpoint { addr, pktinfo });
+    }
+
+    /// Returns the most recently observed endpoint for `peer`, if any.
+    pub fn endpoint(&self, peer: &PublicKey) -> Option<SocketAddr> {
+        self.endpoints
+            .lock()
+            .unwrap()
+            .get(peer.as_bytes())
+            .map(|e| e.addr)
+    }
+
+    /// Returns the sticky source `PktInfo` to reply to `peer` with, if
+    /// we've received an authenticated packet from them yet.
+    pub fn pktinfo(&self, peer: &PublicKey) -> Option<PktInfo> {
+        self.endpoints
+            .lock()
+            .unwrap()
+            .get(peer.as_bytes())
+            .and_then(|e| e.pktinfo)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn peer(byte: u8) -> PublicKey {
+        PublicKey::from([byte; 32])
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port)
+    }
+
+    fn pktinfo(ifindex: i32) -> PktInfo {
+        PktInfo {
+            local_addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            ifindex,
+        }
+    }
+
+    #[test]
+    fn unknown_peer_has_no_endpoint_or_pktinfo() {
+        let table = PeerTable::new();
+        assert_eq!(table.endpoint(&peer(1)), None);
+        assert_eq!(table.pktinfo(&peer(1)).is_none(), true);
+    }
+
+    #[test]
+    fn observe_records_addr_and_pktinfo() {
+        let table = PeerTable::new();
+        table.observe(&peer(1), addr(9090), Some(pktinfo(3)));
+        assert_eq!(table.endpoint(&peer(1)), Some(addr(9090)));
+        assert_eq!(table.pktinfo(&peer(1)).unwrap().ifindex, 3);
+    }
+
+    #[test]
+    fn observe_with_no_pktinfo_preserves_the_previously_learned_one() {
+        let table = PeerTable::new();
+        table.observe(&peer(1), addr(9090), Some(pktinfo(3)));
+
+        // Mirrors a rekey: a fresh endpoint is learned from the handshake
+        // response, but it carries no IP_PKTINFO ancillary data.
+        table.observe(&peer(1), addr(9191), None);
+
+        assert_eq!(table.endpoint(&peer(1)), Some(addr(9191)));
+        assert_eq!(table.pktinfo(&peer(1)).unwrap().ifindex, 3);
+    }
+
+    #[test]
+    fn observe_with_a_new_pktinfo_overwrites_the_old_one() {
+        let table = PeerTable::new();
+        table.observe(&peer(1), addr(9090), Some(pktinfo(3)));
+        table.observe(&peer(1), addr(9090), Some(pktinfo(4)));
+        assert_eq!(table.pktinfo(&peer(1)).unwrap().ifindex, 4);
+    }
+
+    #[test]
+    fn peers_are_tracked_independently() {
+        let table = PeerTable::new();
+        table.observe(&peer(1), addr(9090), Some(pktinfo(3)));
+        table.observe(&peer(2), addr(9191), None);
+        assert_eq!(table.endpoint(&peer(1)), Some(addr(9090)));
+        assert_eq!(table.endpoint(&peer(2)), Some(addr(9191)));
+        assert!(table.pktinfo(&peer(2)).is_none());
+    }
+}
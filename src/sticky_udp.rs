@@ -0,0 +1,199 @@
+//! A UDP socket that can observe and set the local ("sticky") address a
+//! datagram was received on or sent from, via `IP_PKTINFO` /
+//! `IPV6_RECVPKTINFO` ancillary data. `tokio::net::UdpSocket` has no access
+//! to control messages, so this enables the option on a `socket2::Socket`
+//! and drives `recvmsg`/`sendmsg` by hand through `AsyncFd`, matching the
+//! raw-ioctl style the rest of the interface setup already uses.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::raw::c_int;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use nix::sys::socket::{
+    recvmsg, sendmsg, ControlMessage, ControlMessageOwned, InetAddr, MsgFlags, SockAddr,
+};
+use nix::sys::uio::IoVec;
+use socket2::{Domain, Socket, Type};
+use tokio::io::unix::AsyncFd;
+
+/// The local address (and interface) a packet arrived on, or that a reply
+/// should be sent from to stay "sticky" to the same address on a
+/// multi-homed host.
+#[derive(Clone, Copy, Debug)]
+pub struct PktInfo {
+    pub local_addr: IpAddr,
+    pub ifindex: i32,
+}
+
+pub struct StickyUdpSocket {
+    io: AsyncFd<Socket>,
+}
+
+impl StickyUdpSocket {
+    pub fn bind(addr: SocketAddr) -> io::Result<Self> {
+        let domain = if addr.is_ipv4() {
+            Domain::IPV4
+        } else {
+            Domain::IPV6
+        };
+        let socket = Socket::new(domain, Type::DGRAM, None)?;
+        socket.set_reuse_address(true)?;
+        socket.bind(&addr.into())?;
+        set_pktinfo(socket.as_raw_fd(), addr.is_ipv4())?;
+        socket.set_nonblocking(true)?;
+
+        Ok(StickyUdpSocket {
+            io: AsyncFd::new(socket)?,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.io
+            .get_ref()
+            .local_addr()?
+            .as_socket()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "not an inet socket"))
+    }
+
+    /// Receives one datagram, returning its payload length, the peer's
+    /// address, and the local address/interface it arrived on.
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, PktInfo)> {
+        loop {
+            let mut guard = self.io.readable().await?;
+            match guard.try_io(|inner| recv_from_sync(inner.get_ref().as_raw_fd(), buf)) {
+                Ok(result) => return result,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    /// Sends one datagram to `dest`, setting the source address cmsg to
+    /// `pktinfo` when known so the reply stays sticky to the address the
+    /// peer originally talked to.
+    pub async fn send_to(
+        &self,
+        buf: &[u8],
+        dest: SocketAddr,
+        pktinfo: Option<PktInfo>,
+    ) -> io::Result<usize> {
+        loop {
+            let mut guard = self.io.writable().await?;
+            match guard.try_io(|inner| send_to_sync(inner.get_ref().as_raw_fd(), buf, dest, pktinfo)) {
+                Ok(result) => return result,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+fn set_pktinfo(fd: RawFd, is_v4: bool) -> io::Result<()> {
+    let one: c_int = 1;
+    let (level, optname) = if is_v4 {
+        (libc::IPPROTO_IP, libc::IP_PKTINFO)
+    } else {
+        (libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO)
+    };
+    let rc = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            optname,
+            &one as *const c_int as *const libc::c_void,
+            std::mem::size_of::<c_int>() as libc::socklen_t,
+        )
+    };
+    if rc < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn sockaddr_to_std(addr: Option<SockAddr>) -> io::Result<SocketAddr> {
+    match addr {
+        Some(SockAddr::Inet(inet)) => Ok(inet.to_std()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::Other,
+            "recvmsg: missing or non-inet peer address",
+        )),
+    }
+}
+
+fn recv_from_sync(fd: RawFd, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, PktInfo)> {
+    let iov = [IoVec::from_mut_slice(buf)];
+    let mut cmsg_buf = nix::cmsg_space!(libc::in_pktinfo, libc::in6_pktinfo);
+    let msg = recvmsg(fd, &iov, Some(&mut cmsg_buf), MsgFlags::empty())
+        .map_err(|e| io::Error::from_raw_os_error(e as i32))?;
+
+    let peer = sockaddr_to_std(msg.address)?;
+
+    let mut pktinfo = None;
+    for cmsg in msg.cmsgs() {
+        match cmsg {
+            ControlMessageOwned::Ipv4PacketInfo(info) => {
+                pktinfo = Some(PktInfo {
+                    local_addr: IpAddr::V4(Ipv4Addr::from(u32::from_be(info.ipi_addr.s_addr))),
+                    ifindex: info.ipi_ifindex,
+                });
+            }
+            ControlMessageOwned::Ipv6PacketInfo(info) => {
+                pktinfo = Some(PktInfo {
+                    local_addr: IpAddr::V6(Ipv6Addr::from(info.ipi6_addr.s6_addr)),
+                    ifindex: info.ipi6_ifindex as i32,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    let pktinfo = pktinfo.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::Other, "recvmsg: no IP_PKTINFO cmsg present")
+    })?;
+
+    Ok((msg.bytes, peer, pktinfo))
+}
+
+fn send_to_sync(
+    fd: RawFd,
+    buf: &[u8],
+    dest: SocketAddr,
+    pktinfo: Option<PktInfo>,
+) -> io::Result<usize> {
+    let iov = [IoVec::from_slice(buf)];
+    let dest_addr = SockAddr::new_inet(InetAddr::from_std(&dest));
+
+    match pktinfo {
+        Some(PktInfo {
+            local_addr: IpAddr::V4(addr),
+            ifindex,
+        }) => {
+            let info = libc::in_pktinfo {
+                ipi_ifindex: ifindex,
+                ipi_spec_dst: libc::in_addr { s_addr: 0 },
+                ipi_addr: libc::in_addr {
+                    s_addr: u32::from(addr).to_be(),
+                },
+            };
+            let cmsgs = [ControlMessage::Ipv4PacketInfo(&info)];
+            sendmsg(fd, &iov, &cmsgs, MsgFlags::empty(), Some(&dest_addr))
+                .map_err(|e| io::Error::from_raw_os_error(e as i32))
+        }
+        Some(PktInfo {
+            local_addr: IpAddr::V6(addr),
+            ifindex,
+        }) => {
+            let info = libc::in6_pktinfo {
+                ipi6_addr: libc::in6_addr {
+                    s6_addr: addr.octets(),
+                },
+                ipi6_ifindex: ifindex as u32,
+            };
+            let cmsgs = [ControlMessage::Ipv6PacketInfo(&info)];
+            sendmsg(fd, &iov, &cmsgs, MsgFlags::empty(), Some(&dest_addr))
+                .map_err(|e| io::Error::from_raw_os_error(e as i32))
+        }
+        None => sendmsg(fd, &iov, &[], MsgFlags::empty(), Some(&dest_addr))
+            .map_err(|e| io::Error::from_raw_os_error(e as i32)),
+    }
+}
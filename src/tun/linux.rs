@@ -0,0 +1,286 @@
+//! Linux TUN backend: the `ifreq`/`ifr_ifru` unions and `TUNSETIFF` /
+//! `SIOCSIFFLAGS` / `SIOCSIFADDR` / `SIOCSIFNETMASK` / `TUNSETQUEUE` ioctls
+//! that used to be inlined into `main`. This is the only `Tun` impl that
+//! supports multi-queue (`IFF_MULTI_QUEUE` + `TUNSETQUEUE`).
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::os::raw::{c_char, c_int, c_short, c_uchar, c_ulong, c_ushort};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+use libc::*;
+use nix::sys::socket::InetAddr;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+
+use crate::error::Error;
+
+use super::Tun;
+
+macro_rules! ioctl(
+	($fd:expr, $name:expr, $flags:expr, $value:expr) => ({
+		let rc = libc::ioctl($fd, $flags, $value);
+		if rc < 0 {
+			Err(Error::Ioctl {
+				name: $name,
+				fd: $fd,
+				source: std::io::Error::last_os_error(),
+			})
+		} else {
+			Ok(())
+		}
+	})
+);
+
+type IfName = [c_char; IFNAMSIZ];
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+struct ifmap {
+    mem_start: c_ulong,
+    mem_end: c_ulong,
+    base_addr: c_ushort,
+    irq: c_uchar,
+    dma: c_uchar,
+    port: c_uchar,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+union ifr_ifru {
+    ifr_addr: libc::sockaddr,
+    ifr_dstaddr: libc::sockaddr,
+    ifr_broadaddr: libc::sockaddr,
+    ifr_netmask: libc::sockaddr,
+    ifr_hwaddr: libc::sockaddr,
+    ifr_flags: c_short,
+    ifr_ifindex: c_int,
+    ifr_metric: c_int,
+    ifr_mtu: c_int,
+    ifr_map: ifmap,
+    ifr_slave: IfName,
+    ifr_newname: IfName,
+    ifr_data: *mut c_char,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct ifreq {
+    ifr_name: IfName,
+    ifr_ifru: ifr_ifru,
+}
+
+impl ifreq {
+    fn with_if_name(iface: &str) -> ifreq {
+        let mut ifr = ifreq::default();
+        for (a, c) in ifr.ifr_name.iter_mut().zip(iface.bytes()) {
+            *a = c as i8;
+        }
+        ifr
+    }
+}
+
+impl Default for ifreq {
+    fn default() -> ifreq {
+        unsafe { std::mem::zeroed() }
+    }
+}
+
+fn if_name_from_req(req: &ifreq) -> String {
+    let bytes: Vec<u8> = req
+        .ifr_name
+        .iter()
+        .take_while(|&&c| c != 0)
+        .map(|&c| c as u8)
+        .collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+const IFF_UP: i16 = 1;
+const IFF_RUNNING: i16 = 1 << 6;
+
+/* TUNSETIFF ifr flags */
+const IFF_TUN: i16 = 0x0001;
+const IFF_NO_PI: i16 = 0x1000;
+const IFF_MULTI_QUEUE: i16 = 0x0100;
+const IFF_DETACH_QUEUE: i16 = 0x0400;
+
+/* Ioctl defines */
+const TUNSETIFF: u64 = 0x4004_54ca;
+const TUNSETQUEUE: u64 = 0x4004_54d9;
+
+/* Socket configuration controls. */
+const SIOCGIFFLAGS: u64 = 0x8914;
+const SIOCSIFFLAGS: u64 = 0x8914;
+const SIOCSIFADDR: u64 = 0x8916;
+const SIOCSIFNETMASK: u64 = 0x891c;
+const SIOCGIFINDEX: u64 = 0x8933;
+
+/// `ifreq` has no room for a 128-bit address, so IPv6 address assignment
+/// goes through this separate struct on an `AF_INET6` socket instead (the
+/// same `SIOCSIFADDR` ioctl number, just a different request layout).
+#[repr(C)]
+struct in6_ifreq {
+    ifr6_addr: libc::in6_addr,
+    ifr6_prefixlen: u32,
+    ifr6_ifindex: c_int,
+}
+
+/// Closes a raw fd on drop. Used for the short-lived `AF_INET6` socket in
+/// `set_addr` so a failed ioctl (returned early via `?`) doesn't leak it.
+struct FdGuard(RawFd);
+
+impl Drop for FdGuard {
+    fn drop(&mut self) {
+        unsafe {
+            close(self.0);
+        }
+    }
+}
+
+impl AsRawFd for FdGuard {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+pub struct LinuxTun {
+    fd: RawFd,
+    /// `AF_INET` socket used for the `SIOC*` ifreq ioctls below. Wrapped in
+    /// `FdGuard` (the same pattern `set_addr`'s short-lived v6 socket uses)
+    /// so it's closed once on drop instead of leaking for the life of the
+    /// process.
+    ctrl_fd: FdGuard,
+    if_name: String,
+    reader: BufReader<File>,
+    writer: BufWriter<File>,
+}
+
+impl Tun for LinuxTun {
+    async fn open(existing: Option<&str>) -> Result<Self, Error> {
+        let tun_file = File::open("/dev/net/tun").await.map_err(Error::TunIo)?;
+        let fd = tun_file.as_raw_fd();
+
+        let mut req = ifreq::with_if_name(existing.unwrap_or(""));
+        req.ifr_ifru.ifr_flags = IFF_TUN | IFF_NO_PI | IFF_MULTI_QUEUE;
+        unsafe { ioctl!(fd, "TUNSETIFF", TUNSETIFF, &req) }?;
+        let if_name = if_name_from_req(&req);
+
+        const IPPROTO_IP: c_int = 0;
+        let ctrl_fd = unsafe { socket(AF_INET, SOCK_DGRAM, IPPROTO_IP) };
+        if ctrl_fd < 0 {
+            return Err(Error::Ioctl {
+                name: "socket(AF_INET)",
+                fd: ctrl_fd,
+                source: std::io::Error::last_os_error(),
+            });
+        }
+
+        // `reader` keeps `tun_file` as the one handle that owns `fd`; `writer`
+        // gets its own independently-owned duplicate instead of a second
+        // `File` wrapping the same descriptor, which would double-close it
+        // on drop.
+        let write_fd = unsafe { dup(fd) };
+        if write_fd < 0 {
+            return Err(Error::Ioctl {
+                name: "dup",
+                fd,
+                source: std::io::Error::last_os_error(),
+            });
+        }
+
+        Ok(LinuxTun {
+            fd,
+            ctrl_fd: FdGuard(ctrl_fd),
+            if_name,
+            reader: BufReader::new(tun_file),
+            writer: BufWriter::new(unsafe { File::from_raw_fd(write_fd) }),
+        })
+    }
+
+    fn name(&self) -> &str {
+        &self.if_name
+    }
+
+    fn set_flags(&self, up: bool) -> Result<(), Error> {
+        let mut req = ifreq::with_if_name(&self.if_name);
+        unsafe {
+            ioctl!(self.ctrl_fd.as_raw_fd(), "SIOCGIFFLAGS", SIOCGIFFLAGS, &req)?;
+            if up {
+                req.ifr_ifru.ifr_flags |= IFF_UP | IFF_RUNNING;
+            } else {
+                req.ifr_ifru.ifr_flags &= !(IFF_UP | IFF_RUNNING);
+            }
+            ioctl!(self.ctrl_fd.as_raw_fd(), "SIOCSIFFLAGS", SIOCSIFFLAGS, &req)
+        }
+    }
+
+    fn set_addr(&self, addr: IpAddr, prefix_len: u8) -> Result<(), Error> {
+        match addr {
+            IpAddr::V4(v4) => {
+                let mut req = ifreq::with_if_name(&self.if_name);
+                let inet = InetAddr::from_std(&(v4, 0).into());
+                if let InetAddr::V4(sockaddr_in) = inet {
+                    unsafe {
+                        req.ifr_ifru.ifr_addr = std::mem::transmute(sockaddr_in);
+                        ioctl!(self.ctrl_fd.as_raw_fd(), "SIOCSIFADDR", SIOCSIFADDR, &req)?;
+                    }
+                }
+                Ok(())
+            }
+            IpAddr::V6(v6) => {
+                const IPPROTO_IP: c_int = 0;
+                let sock6 = unsafe { socket(AF_INET6, SOCK_DGRAM, IPPROTO_IP) };
+                if sock6 < 0 {
+                    return Err(Error::Ioctl {
+                        name: "socket(AF_INET6)",
+                        fd: sock6,
+                        source: std::io::Error::last_os_error(),
+                    });
+                }
+                // Closes `sock6` on every return path below, including the
+                // `?`-propagated ioctl failures, so a failed v6 address
+                // configuration doesn't leak the socket.
+                let _sock6 = FdGuard(sock6);
+
+                let mut name_req = ifreq::with_if_name(&self.if_name);
+                unsafe { ioctl!(sock6, "SIOCGIFINDEX", SIOCGIFINDEX, &mut name_req) }?;
+                let ifindex = unsafe { name_req.ifr_ifru.ifr_ifindex };
+
+                let ifr6 = in6_ifreq {
+                    ifr6_addr: libc::in6_addr {
+                        s6_addr: v6.octets(),
+                    },
+                    ifr6_prefixlen: prefix_len as u32,
+                    ifr6_ifindex: ifindex,
+                };
+                unsafe { ioctl!(sock6, "SIOCSIFADDR(v6)", SIOCSIFADDR, &ifr6) }
+            }
+        }
+    }
+
+    fn set_netmask(&self, netmask: Ipv4Addr) -> Result<(), Error> {
+        let mut req = ifreq::with_if_name(&self.if_name);
+        let inet = InetAddr::from_std(&(netmask, 0).into());
+        if let InetAddr::V4(sockaddr_in) = inet {
+            unsafe {
+                req.ifr_ifru.ifr_netmask = std::mem::transmute(sockaddr_in);
+                ioctl!(self.ctrl_fd.as_raw_fd(), "SIOCSIFNETMASK", SIOCSIFNETMASK, &req)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn detach(&self) -> Result<(), Error> {
+        let mut req = ifreq::default();
+        req.ifr_ifru.ifr_flags = IFF_DETACH_QUEUE;
+        unsafe { ioctl!(self.fd, "TUNSETQUEUE", TUNSETQUEUE, &req) }
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.reader.read(buf).await.map_err(Error::TunIo)
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.writer.write(buf).await.map_err(Error::TunIo)
+    }
+}
@@ -0,0 +1,214 @@
+//! macOS TUN backend. Darwin has no `/dev/net/tun`; instead you open a
+//! `utun` device through the `AF_SYSTEM`/`SYSPROTO_CONTROL` kernel control
+//! socket (the same mechanism wireguard-go and boringtun use on macOS).
+//! There's no multi-queue concept here, so this backend only ever opens
+//! one queue per interface.
+//!
+//! Address assignment goes through `ifconfig` rather than hand-rolling the
+//! BSD `SIOCAIFADDR`/`in_aliasreq` ioctl layout Linux's `ifreq` doesn't
+//! share — a deliberate simplification for this second backend, not a
+//! claim that it's the most efficient path.
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::os::raw::c_void;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::process::Command;
+
+use libc::*;
+use tokio::io::unix::AsyncFd;
+
+use crate::error::Error;
+
+use super::Tun;
+
+const UTUN_CONTROL_NAME: &str = "com.apple.net.utun_control";
+const UTUN_OPT_IFNAME: c_int = 2;
+
+pub struct MacosTun {
+    io: AsyncFd<RawFdHandle>,
+    if_name: String,
+}
+
+/// A bare fd wrapper so `AsyncFd` has something `AsRawFd` to poll; `utun`
+/// sockets aren't `std::net` types so there's no existing wrapper to reuse.
+struct RawFdHandle(RawFd);
+
+impl AsRawFd for RawFdHandle {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl Drop for RawFdHandle {
+    fn drop(&mut self) {
+        unsafe {
+            close(self.0);
+        }
+    }
+}
+
+impl Tun for MacosTun {
+    async fn open(existing: Option<&str>) -> Result<Self, Error> {
+        if existing.is_some() {
+            return Err(Error::Unsupported("utun has no multi-queue concept"));
+        }
+
+        let fd = unsafe { socket(PF_SYSTEM, SOCK_DGRAM, SYSPROTO_CONTROL) };
+        if fd < 0 {
+            return Err(io_err("socket(PF_SYSTEM)", fd));
+        }
+
+        let mut info: ctl_info = unsafe { std::mem::zeroed() };
+        let name_bytes = UTUN_CONTROL_NAME.as_bytes();
+        for (dst, &src) in info.ctl_name.iter_mut().zip(name_bytes) {
+            *dst = src as i8;
+        }
+        let rc = unsafe { ioctl(fd, CTLIOCGINFO, &mut info as *mut _ as *mut c_void) };
+        if rc < 0 {
+            return Err(io_err("CTLIOCGINFO", fd));
+        }
+
+        let mut addr: sockaddr_ctl = unsafe { std::mem::zeroed() };
+        addr.sc_len = std::mem::size_of::<sockaddr_ctl>() as u8;
+        addr.sc_family = AF_SYSTEM as u8;
+        addr.ss_sysaddr = AF_SYS_CONTROL as u16;
+        addr.sc_id = info.ctl_id;
+        addr.sc_unit = 0; // ask the kernel to assign the next free utunN
+
+        let rc = unsafe {
+            connect(
+                fd,
+                &addr as *const sockaddr_ctl as *const sockaddr,
+                std::mem::size_of::<sockaddr_ctl>() as socklen_t,
+            )
+        };
+        if rc < 0 {
+            return Err(io_err("connect(utun)", fd));
+        }
+
+        let mut name_buf = [0u8; 16];
+        let mut name_len = name_buf.len() as socklen_t;
+        let rc = unsafe {
+            getsockopt(
+                fd,
+                SYSPROTO_CONTROL,
+                UTUN_OPT_IFNAME,
+                name_buf.as_mut_ptr() as *mut c_void,
+                &mut name_len,
+            )
+        };
+        if rc < 0 {
+            return Err(io_err("getsockopt(UTUN_OPT_IFNAME)", fd));
+        }
+        let if_name = String::from_utf8_lossy(&name_buf[..name_len as usize - 1]).into_owned();
+
+        let flags = unsafe { fcntl(fd, F_GETFL, 0) };
+        unsafe { fcntl(fd, F_SETFL, flags | O_NONBLOCK) };
+
+        Ok(MacosTun {
+            io: AsyncFd::new(RawFdHandle(fd)).map_err(Error::TunIo)?,
+            if_name,
+        })
+    }
+
+    fn name(&self) -> &str {
+        &self.if_name
+    }
+
+    fn set_flags(&self, up: bool) -> Result<(), Error> {
+        run_ifconfig(&[&self.if_name, if up { "up" } else { "down" }])
+    }
+
+    fn set_addr(&self, addr: IpAddr, prefix_len: u8) -> Result<(), Error> {
+        match addr {
+            IpAddr::V4(v4) => run_ifconfig(&[&self.if_name, &v4.to_string(), &v4.to_string()]),
+            IpAddr::V6(v6) => run_ifconfig(&[
+                &self.if_name,
+                "inet6",
+                &format!("{}/{}", v6, prefix_len),
+            ]),
+        }
+    }
+
+    fn set_netmask(&self, _netmask: Ipv4Addr) -> Result<(), Error> {
+        // utun point-to-point interfaces take their mask from the paired
+        // addresses passed to `set_addr`; there's no separate netmask step.
+        Ok(())
+    }
+
+    fn detach(&self) -> Result<(), Error> {
+        // No multi-queue registration to undo on this backend.
+        Ok(())
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        loop {
+            let mut guard = self.io.readable().await.map_err(Error::TunIo)?;
+            match guard.try_io(|inner| {
+                let rc = unsafe {
+                    recv(
+                        inner.get_ref().as_raw_fd(),
+                        buf.as_mut_ptr() as *mut c_void,
+                        buf.len(),
+                        0,
+                    )
+                };
+                if rc < 0 {
+                    Err(std::io::Error::last_os_error())
+                } else {
+                    Ok(rc as usize)
+                }
+            }) {
+                Ok(result) => return result.map_err(Error::TunIo),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        loop {
+            let mut guard = self.io.writable().await.map_err(Error::TunIo)?;
+            match guard.try_io(|inner| {
+                let rc = unsafe {
+                    send(
+                        inner.get_ref().as_raw_fd(),
+                        buf.as_ptr() as *const c_void,
+                        buf.len(),
+                        0,
+                    )
+                };
+                if rc < 0 {
+                    Err(std::io::Error::last_os_error())
+                } else {
+                    Ok(rc as usize)
+                }
+            }) {
+                Ok(result) => return result.map_err(Error::TunIo),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+fn io_err(name: &'static str, fd: RawFd) -> Error {
+    Error::Ioctl {
+        name,
+        fd,
+        source: std::io::Error::last_os_error(),
+    }
+}
+
+fn run_ifconfig(args: &[&str]) -> Result<(), Error> {
+    let status = Command::new("ifconfig")
+        .args(args)
+        .status()
+        .map_err(Error::TunIo)?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::TunIo(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("ifconfig {:?} exited with {}", args, status),
+        )))
+    }
+}
@@ -0,0 +1,58 @@
+//! Platform-independent TUN device API. All the OS-specific plumbing
+//! (ifreq/ioctl unions on Linux, the `utun` control socket on macOS, ...)
+//! lives in a per-OS `sys` module behind the `Tun` trait; `main`'s async
+//! forwarding core only ever talks to the trait, the same split portable
+//! socket crates use between a common API and per-OS backends.
+
+use std::net::{IpAddr, Ipv4Addr};
+
+use crate::error::Error;
+
+#[cfg(target_os = "linux")]
+pub mod linux;
+
+#[cfg(target_os = "macos")]
+pub mod macos;
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub mod stub;
+
+#[cfg(target_os = "linux")]
+pub type PlatformTun = linux::LinuxTun;
+
+#[cfg(target_os = "macos")]
+pub type PlatformTun = macos::MacosTun;
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub type PlatformTun = stub::StubTun;
+
+/// A single TUN device/queue. On platforms with multi-queue support
+/// (Linux), opening with `existing = Some(name)` attaches another queue to
+/// an already-created interface instead of creating a new one.
+pub trait Tun: Sized + Send {
+    /// Opens a TUN device. When `existing` is `None` a new interface is
+    /// created (and its kernel-assigned name is returned from `name()`);
+    /// when `Some(name)`, attaches an additional queue to that interface.
+    async fn open(existing: Option<&str>) -> Result<Self, Error>;
+
+    /// The interface name this device is attached to.
+    fn name(&self) -> &str;
+
+    /// Brings the interface administratively up or down. Interface-level
+    /// state, so only needs calling once even with multiple queues open.
+    fn set_flags(&self, up: bool) -> Result<(), Error>;
+
+    /// Assigns `addr/prefix_len` to the interface.
+    fn set_addr(&self, addr: IpAddr, prefix_len: u8) -> Result<(), Error>;
+
+    /// Sets the IPv4 netmask (IPv6 carries its prefix length in `set_addr`
+    /// instead, so this is a no-op for v6-only backends).
+    fn set_netmask(&self, netmask: Ipv4Addr) -> Result<(), Error>;
+
+    /// Detaches this queue from the interface. A no-op on backends with no
+    /// multi-queue concept.
+    fn detach(&self) -> Result<(), Error>;
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error>;
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Error>;
+}
@@ -0,0 +1,48 @@
+//! Fallback backend for targets with no TUN implementation here yet
+//! (Windows, BSDs, ...). Keeps the crate compiling everywhere the `Tun`
+//! trait is referenced generically; every operation reports
+//! `Error::Unsupported` rather than silently doing nothing.
+
+use std::net::{IpAddr, Ipv4Addr};
+
+use crate::error::Error;
+
+use super::Tun;
+
+pub struct StubTun;
+
+impl Tun for StubTun {
+    async fn open(_existing: Option<&str>) -> Result<Self, Error> {
+        Err(Error::Unsupported(
+            "no Tun backend implemented for this platform",
+        ))
+    }
+
+    fn name(&self) -> &str {
+        ""
+    }
+
+    fn set_flags(&self, _up: bool) -> Result<(), Error> {
+        Err(Error::Unsupported("set_flags"))
+    }
+
+    fn set_addr(&self, _addr: IpAddr, _prefix_len: u8) -> Result<(), Error> {
+        Err(Error::Unsupported("set_addr"))
+    }
+
+    fn set_netmask(&self, _netmask: Ipv4Addr) -> Result<(), Error> {
+        Err(Error::Unsupported("set_netmask"))
+    }
+
+    fn detach(&self) -> Result<(), Error> {
+        Err(Error::Unsupported("detach"))
+    }
+
+    async fn read(&mut self, _buf: &mut [u8]) -> Result<usize, Error> {
+        Err(Error::Unsupported("read"))
+    }
+
+    async fn write(&mut self, _buf: &[u8]) -> Result<usize, Error> {
+        Err(Error::Unsupported("write"))
+    }
+}